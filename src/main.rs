@@ -1,8 +1,115 @@
 use clap::{Parser, Subcommand};
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fmt, fs::File};
 
-type Database = HashMap<String, String>;
+type Database = HashMap<String, Value>;
+
+/// A value stored against a key. Keeping this as an enum (rather than
+/// always coercing to `String`) lets a round trip through any serializer
+/// preserve whether a value was a number, a bool, or a list instead of
+/// flattening everything to text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Parses a raw CLI argument into the narrowest `Value` variant it matches,
+/// trying `bool`, then `i64`, then `f64` before falling back to `Str`.
+fn parse_value(raw: &str) -> Value {
+    if let Ok(b) = raw.parse::<bool>() {
+        Value::Bool(b)
+    } else if let Ok(i) = raw.parse::<i64>() {
+        Value::Int(i)
+    } else if let Ok(n) = raw.parse::<f64>() {
+        if n.is_finite() {
+            Value::Float(n)
+        } else {
+            // `NaN`/`inf` parse as valid `f64`s but aren't valid JSON numbers;
+            // storing them as a string keeps every serializer round-trippable.
+            Value::Str(raw.to_string())
+        }
+    } else {
+        Value::Str(raw.to_string())
+    }
+}
+
+/// Short discriminant stored alongside the value in serializers (CSV,
+/// SQLite) that don't otherwise carry type information.
+fn value_kind(value: &Value) -> &'static str {
+    match value {
+        Value::Str(_) => "str",
+        Value::Int(_) => "int",
+        Value::Float(_) => "float",
+        Value::Bool(_) => "bool",
+        Value::List(_) => "list",
+    }
+}
+
+fn value_to_cell(value: &Value) -> String {
+    match value {
+        Value::Str(s) => s.clone(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::List(_) => {
+            serde_json::to_string(value).expect("List values are always JSON-serializable")
+        }
+    }
+}
+
+fn value_from_cell(kind: &str, cell: &str) -> Result<Value> {
+    match kind {
+        "str" => Ok(Value::Str(cell.to_string())),
+        "int" => cell
+            .parse::<i64>()
+            .map(Value::Int)
+            .map_err(|_| invalid_value(kind, cell)),
+        "float" => cell
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| invalid_value(kind, cell)),
+        "bool" => cell
+            .parse::<bool>()
+            .map(Value::Bool)
+            .map_err(|_| invalid_value(kind, cell)),
+        "list" => serde_json::from_str(cell).map_err(Error::from),
+        _ => Err(invalid_value(kind, cell)),
+    }
+}
+
+fn invalid_value(kind: &str, cell: &str) -> Error {
+    Error::InvalidValue(InvalidValue {
+        kind: kind.to_string(),
+        cell: cell.to_string(),
+    })
+}
 
 /// A fictional versioning CLI
 #[derive(Parser)]
@@ -33,6 +140,13 @@ enum Commands {
     /// adds things
     List {},
     Init {},
+    /// converts the database to another format
+    #[clap(arg_required_else_help = true)]
+    Convert {
+        /// The file to write the converted database to; its extension
+        /// selects the output serializer the same way `database` does
+        output: String,
+    },
 }
 
 fn main() {
@@ -47,6 +161,9 @@ fn main() {
         Commands::Remove { key } => remove_db_key(&cli_args.database, &key, serializer.as_ref()),
         Commands::List {} => list_db(&cli_args.database, serializer.as_ref()),
         Commands::Init {} => init_db(&cli_args.database, serializer.as_ref()),
+        Commands::Convert { output } => {
+            convert_db(&cli_args.database, &output, serializer.as_ref())
+        }
     }
     .unwrap()
 }
@@ -59,6 +176,12 @@ pub struct KeyDoesNotExist {
     key: String,
 }
 
+#[derive(Debug)]
+pub struct InvalidValue {
+    kind: String,
+    cell: String,
+}
+
 #[derive(Debug)]
 enum Error {
     IO(std::io::Error),
@@ -66,8 +189,11 @@ enum Error {
     SerdeJson(serde_json::Error),
     Sql(rusqlite::Error),
     Csv(csv::Error),
+    MsgPackEncode(rmp_serde::encode::Error),
+    MsgPackDecode(rmp_serde::decode::Error),
     KeyAlreadyExists(KeyAlreadyExists),
     KeyDoesNotExist(KeyDoesNotExist),
+    InvalidValue(InvalidValue),
 }
 
 impl fmt::Display for KeyDoesNotExist {
@@ -76,6 +202,12 @@ impl fmt::Display for KeyDoesNotExist {
     }
 }
 
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not parse value '{}' as a '{}'", self.cell, self.kind)
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 impl From<std::io::Error> for Error {
@@ -108,6 +240,18 @@ impl From<csv::Error> for Error {
     }
 }
 
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(err: rmp_serde::encode::Error) -> Error {
+        Error::MsgPackEncode(err)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(err: rmp_serde::decode::Error) -> Error {
+        Error::MsgPackDecode(err)
+    }
+}
+
 impl From<KeyDoesNotExist> for Error {
     fn from(err: KeyDoesNotExist) -> Error {
         Error::KeyDoesNotExist(err)
@@ -120,21 +264,65 @@ impl From<KeyAlreadyExists> for Error {
     }
 }
 
-fn create_serializer(fname: &str) -> Box<dyn Serializer> {
+impl From<InvalidValue> for Error {
+    fn from(err: InvalidValue) -> Error {
+        Error::InvalidValue(err)
+    }
+}
+
+#[derive(Debug)]
+enum SerializerKind {
+    Yaml,
+    Json,
+    Sqlite,
+    Csv,
+    MsgPack,
+}
+
+fn serializer_kind(fname: &str) -> SerializerKind {
     if fname.ends_with(".yml") {
-        Box::new(YamlSerializer {})
+        SerializerKind::Yaml
     } else if fname.ends_with(".json") {
-        Box::new(JsonSerializer {})
+        SerializerKind::Json
     } else if fname.ends_with(".db") || fname.ends_with(".sqlite") {
-        Box::new(SqliteSerializer {})
+        SerializerKind::Sqlite
     } else if fname.ends_with(".csv") {
-        Box::new(CsvSerializer {})
+        SerializerKind::Csv
+    } else if fname.ends_with(".msgpack") || fname.ends_with(".mp") {
+        SerializerKind::MsgPack
     } else {
         println!("Could not determine serializer, falling back to yaml");
-        Box::new(YamlSerializer {})
+        SerializerKind::Yaml
     }
 }
 
+fn serializer_for_kind(kind: &SerializerKind) -> Box<dyn Serializer> {
+    match kind {
+        SerializerKind::Yaml => Box::new(YamlSerializer {}),
+        SerializerKind::Json => Box::new(JsonSerializer {}),
+        SerializerKind::Sqlite => Box::new(SqliteSerializer {}),
+        SerializerKind::Csv => Box::new(CsvSerializer {}),
+        SerializerKind::MsgPack => Box::new(MsgPackSerializer {}),
+    }
+}
+
+fn create_serializer(fname: &str) -> Box<dyn Serializer> {
+    serializer_for_kind(&serializer_kind(fname))
+}
+
+/// Reads `fname` through its auto-detected serializer and re-emits the
+/// whole database through `output`'s. Always goes through a full
+/// decode/re-encode, even when both paths resolve to the same backend.
+/// Duplicate keys in the source are rejected only where its serializer
+/// rejects them (the CSV and SQLite readers); the JSON and MessagePack
+/// readers keep the last value for a repeated key.
+fn convert_db(fname: &str, output: &str, serializer: &dyn Serializer) -> Result<()> {
+    let db = serializer.read_from_file(fname)?;
+    serializer_for_kind(&serializer_kind(output)).write_to_file(output, db)?;
+    println!("Successfully converted '{}' to '{}'", fname, output);
+    Ok(())
+}
+
 fn list_db(fname: &str, serializer: &dyn Serializer) -> Result<()> {
     let db = serializer.read_from_file(fname)?;
     println!("Database contains {} entries", db.len());
@@ -159,19 +347,20 @@ fn remove_db_key(fname: &str, key: &str, serializer: &dyn Serializer) -> Result<
 
 fn init_db(fname: &str, serializer: &dyn Serializer) -> Result<()> {
     let mut db = Database::new();
-    db.insert("hat".to_string(), "fedora".to_string());
-    db.insert("food".to_string(), "hotdog".to_string());
+    db.insert("hat".to_string(), Value::Str("fedora".to_string()));
+    db.insert("food".to_string(), Value::Str("hotdog".to_string()));
     serializer.write_to_file(fname, db)
 }
 
 fn add_key(fname: &str, key: String, value: String, serializer: &dyn Serializer) -> Result<()> {
     let mut db = serializer.read_from_file(fname)?;
-    let res = db.insert(key.clone(), value.clone());
+    let parsed_value = parse_value(&value);
+    let res = db.insert(key.clone(), parsed_value.clone());
     if res.is_some() {
         return Err(Error::KeyAlreadyExists(KeyAlreadyExists {}));
     }
     serializer.write_to_file(fname, db)?;
-    println!("Successfully added key/value {}:{}", key, value);
+    println!("Successfully added key/value {}:{}", key, parsed_value);
     Ok(())
 }
 
@@ -180,13 +369,29 @@ trait Serializer {
     fn read_from_file(&self, fname: &str) -> Result<Database>;
 }
 
+/// Writes `bytes` to a sibling temp file, fsyncs it, then renames it over
+/// `fname`. Rename is atomic on a single filesystem, so a crash or error
+/// mid-write leaves either the old file or the new one, never a half
+/// written one.
+fn atomic_write(fname: &str, bytes: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let tmp_fname = format!("{}.tmp-{}", fname, std::process::id());
+    {
+        let mut tmp_file = File::create(&tmp_fname)?;
+        tmp_file.write_all(bytes)?;
+        tmp_file.sync_all()?;
+    }
+    std::fs::rename(&tmp_fname, fname)?;
+    Ok(())
+}
+
 pub struct YamlSerializer {}
 
 impl Serializer for YamlSerializer {
     fn write_to_file(&self, fname: &str, db: Database) -> Result<()> {
         let s = serde_yaml::to_string(&db)?;
-        std::fs::write(fname, s)?;
-        Ok(())
+        atomic_write(fname, s.as_bytes())
     }
 
     fn read_from_file(&self, fname: &str) -> Result<Database> {
@@ -201,8 +406,7 @@ pub struct JsonSerializer {}
 impl Serializer for JsonSerializer {
     fn write_to_file(&self, fname: &str, db: Database) -> Result<()> {
         let s = serde_json::to_string(&db)?;
-        std::fs::write(fname, s)?;
-        Ok(())
+        atomic_write(fname, s.as_bytes())
     }
 
     fn read_from_file(&self, fname: &str) -> Result<Database> {
@@ -212,27 +416,70 @@ impl Serializer for JsonSerializer {
     }
 }
 
+/// Ordered, up-only migrations for the `kvstore` table. The database's
+/// `PRAGMA user_version` tracks how many of these have been applied; only
+/// migrations past that index are run, inside a single transaction.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS kvstore(
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        key TEXT UNIQUE NOT NULL,
+        value TEXT NOT NULL
+    )",
+    "ALTER TABLE kvstore ADD COLUMN kind TEXT NOT NULL DEFAULT 'str'",
+];
+
+fn apply_migrations(conn: &Connection) -> Result<()> {
+    let mut version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    // A `kvstore` table with no recorded version predates this migration
+    // subsystem; treat it as already having migration 1 applied instead of
+    // re-running the `CREATE TABLE`.
+    if version == 0 {
+        let legacy_table_exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type='table' AND name='kvstore')",
+            [],
+            |row| row.get(0),
+        )?;
+        if legacy_table_exists {
+            version = 1;
+        }
+    }
+
+    if version < MIGRATIONS.len() {
+        let t = conn.unchecked_transaction()?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            t.execute(migration, [])?;
+            t.pragma_update(None, "user_version", (i + 1) as i64)?;
+        }
+        t.commit()?;
+    }
+
+    Ok(())
+}
+
 pub struct SqliteSerializer {}
 
 impl Serializer for SqliteSerializer {
+    // Durability here comes from the transaction rather than `atomic_write`:
+    // the delete and inserts only become visible on `t.commit()`, and `Transaction`
+    // rolls back on drop, so a failed commit never leaves `kvstore` partially
+    // populated.
     fn write_to_file(&self, fname: &str, db: Database) -> Result<()> {
         let mut conn = Connection::open(fname)?;
+        apply_migrations(&conn)?;
+
         let t = conn.transaction()?;
         {
-            t.execute("DROP TABLE IF EXISTS kvstore", [])?;
-
-            t.execute(
-                "CREATE TABLE kvstore(
-                    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
-                    key TEXT UNIQUE NOT NULL,
-                    value TEXT NOT NULL
-                )",
-                [],
-            )?;
-
-            let mut insert_stmt = t.prepare("INSERT INTO kvstore (key, value) VALUES (?,?)")?;
-            for kv in db {
-                insert_stmt.execute(&[kv.0.as_str(), kv.1.as_str()])?;
+            // The write is authoritative: clear the table before inserting so a
+            // key removed from `db` (e.g. by `remove_db_key`) doesn't survive
+            // on disk.
+            t.execute("DELETE FROM kvstore", [])?;
+
+            let mut insert_stmt =
+                t.prepare("INSERT INTO kvstore (key, value, kind) VALUES (?,?,?)")?;
+            for (key, value) in db {
+                let cell = value_to_cell(&value);
+                insert_stmt.execute(&[key.as_str(), cell.as_str(), value_kind(&value)])?;
             }
         }
         t.commit()?;
@@ -242,16 +489,22 @@ impl Serializer for SqliteSerializer {
 
     fn read_from_file(&self, fname: &str) -> Result<Database> {
         let conn = Connection::open(fname)?;
+        apply_migrations(&conn)?;
 
-        let mut stmt = conn.prepare("SELECT key, value FROM kvstore")?;
+        let mut stmt = conn.prepare("SELECT key, value, kind FROM kvstore")?;
         let kv_iter = stmt.query_map([], |row| {
-            Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+            Ok((
+                row.get::<usize, String>(0)?,
+                row.get::<usize, String>(1)?,
+                row.get::<usize, String>(2)?,
+            ))
         })?;
 
         let mut db = Database::new();
         for row in kv_iter {
-            let kv = row?;
-            let res = db.insert(kv.0, kv.1);
+            let (key, value, kind) = row?;
+            let value = value_from_cell(&kind, &value)?;
+            let res = db.insert(key, value);
             if res.is_some() {
                 return Err(Error::KeyAlreadyExists(KeyAlreadyExists {}));
             }
@@ -264,12 +517,13 @@ pub struct CsvSerializer {}
 
 impl Serializer for CsvSerializer {
     fn write_to_file(&self, fname: &str, db: Database) -> Result<()> {
-        let mut wtr = csv::Writer::from_writer(File::create(fname)?);
-        wtr.write_record(&["key", "value"])?;
-        for row in db {
-            wtr.write_record(&[row.0, row.1])?;
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.write_record(&["key", "kind", "value"])?;
+        for (key, value) in db {
+            wtr.write_record(&[key, value_kind(&value).to_string(), value_to_cell(&value)])?;
         }
-        Ok(())
+        let bytes = wtr.into_inner().map_err(|e| e.into_error())?;
+        atomic_write(fname, &bytes)
     }
 
     fn read_from_file(&self, fname: &str) -> Result<Database> {
@@ -277,8 +531,9 @@ impl Serializer for CsvSerializer {
         let mut db = Database::new();
         for row in rdr.records() {
             let kv = row?;
-            assert_eq!(kv.len(), 2);
-            let res = db.insert(kv[0].to_string(), kv[1].to_string());
+            assert_eq!(kv.len(), 3);
+            let value = value_from_cell(&kv[1], &kv[2])?;
+            let res = db.insert(kv[0].to_string(), value);
             if res.is_some() {
                 return Err(Error::KeyAlreadyExists(KeyAlreadyExists {}));
             }
@@ -286,3 +541,18 @@ impl Serializer for CsvSerializer {
         Ok(db)
     }
 }
+
+pub struct MsgPackSerializer {}
+
+impl Serializer for MsgPackSerializer {
+    fn write_to_file(&self, fname: &str, db: Database) -> Result<()> {
+        let bytes = rmp_serde::to_vec(&db)?;
+        atomic_write(fname, &bytes)
+    }
+
+    fn read_from_file(&self, fname: &str) -> Result<Database> {
+        let bytes = std::fs::read(fname)?;
+        let db: Database = rmp_serde::from_slice(&bytes)?;
+        Ok(db)
+    }
+}